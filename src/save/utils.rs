@@ -1,12 +1,16 @@
 //! A package containing useful utilities for writing SRAM accessors. This is
 //! mainly used internally, although the types inside are exposed publically.
 
+use crate::io::irq::{IrqFlags, IE};
 use crate::io::timers::*;
-use crate::sync::{Static, RawMutex, RawMutexGuard};
+use crate::sync::{Static, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use super::Error;
 use voladdress::VolAddress;
 
 /// Internal representation for our active timer.
+///
+/// The timer after this one (e.g. `T1` for `T0`) is reserved to cascade off
+/// of it, extending it to a free-running 32-bit virtual counter.
 #[derive(Copy, Clone, PartialEq)]
 #[repr(u8)]
 enum TimerId {
@@ -16,94 +20,233 @@ enum TimerId {
     T2,
     T3,
 }
+impl TimerId {
+    fn regs(self) -> (VolAddress<u16>, VolAddress<TimerControlSetting>) {
+        match self {
+            TimerId::None => (unsafe { VolAddress::new(0) }, unsafe { VolAddress::new(0) }),
+            TimerId::T0 => (TM0CNT_L, TM0CNT_H),
+            TimerId::T1 => (TM1CNT_L, TM1CNT_H),
+            TimerId::T2 => (TM2CNT_L, TM2CNT_H),
+            TimerId::T3 => (TM3CNT_L, TM3CNT_H),
+        }
+    }
+
+    /// The timer cascaded off of this one to extend it to 32 bits.
+    fn cascade(self) -> TimerId {
+        match self {
+            TimerId::None => TimerId::None,
+            TimerId::T0 => TimerId::T1,
+            TimerId::T1 => TimerId::T2,
+            TimerId::T2 => TimerId::T3,
+            TimerId::T3 => unreachable!("T3 has no timer left to cascade into"),
+        }
+    }
+
+    /// Enables this timer's overflow interrupt in the `IE` register, without
+    /// disturbing any other interrupt source the game may have enabled.
+    ///
+    /// This gives `Halt` a wakeup source of its own: without it, a caller
+    /// that hasn't separately enabled some other interrupt (such as VBlank)
+    /// would `Halt` forever, since nothing would ever raise an interrupt to
+    /// return from it. The interrupt fires each time this timer overflows
+    /// (every 65536 ticks, about 4 seconds), not at any particular deadline.
+    fn enable_irq(self) {
+        let flags = match self {
+            TimerId::None => return,
+            TimerId::T0 => IE.read().with_timer0(true),
+            TimerId::T1 => IE.read().with_timer1(true),
+            TimerId::T2 => IE.read().with_timer2(true),
+            TimerId::T3 => IE.read().with_timer3(true),
+        };
+        IE.write(flags);
+    }
+}
 
 /// Stores the timer ID used for SRAM timeouts.
 static TIMER_ID: Static<TimerId> = Static::new(TimerId::None);
 
 /// Sets the timer to use to implement timeouts for operations that may hang.
 ///
-/// This timer may be used by any SRAM operation.
+/// This timer may be used by any SRAM operation. The next timer (e.g. `1` if
+/// `0` is passed here) is reserved to cascade off of it, so together they
+/// form a free-running 32-bit counter that multiple [`Timeout`]s can read
+/// independently.
 pub fn set_timer_for_timeout(id: u8) {
-    if id >= 4 {
-        panic!("Timer ID must be 0-3.");
+    if id >= 3 {
+        panic!("Timer ID must be 0-2, as the following timer is reserved to extend it to 32 bits.");
     } else {
-        TIMER_ID.write([TimerId::T0, TimerId::T1, TimerId::T2, TimerId::T3][id as usize])
+        let new_id = [TimerId::T0, TimerId::T1, TimerId::T2][id as usize];
+        reset_virtual_ticks_on_change(new_id);
+        TIMER_ID.write(new_id);
     }
 }
 
 /// Disables the timeout for operations that may hang.
 pub fn disable_timeout() {
+    reset_virtual_ticks_on_change(TimerId::None);
     TIMER_ID.write(TimerId::None);
 }
 
+/// Resets [`VIRTUAL_TICKS`] if the configured timer is about to change to
+/// `new_id`.
+///
+/// [`VIRTUAL_TICKS`] is a watermark over whatever timer pair is currently
+/// configured; if it were left untouched across a switch to a different
+/// pair, the new pair's raw count (starting near 0) would read back as the
+/// old pair's much larger watermark until the new count caught up, freezing
+/// elapsed-time math for every [`Timeout`] for a long stretch.
+fn reset_virtual_ticks_on_change(new_id: TimerId) {
+    if TIMER_ID.read() != new_id {
+        VIRTUAL_TICKS.write(0);
+    }
+}
+
+/// The last 32-bit virtual tick count observed by any [`Timeout`].
+///
+/// The low and high halves of the cascaded timer pair can't be read
+/// atomically, so on the rare occasion the high half increments between the
+/// two reads, the raw reading can appear to jump backward by one overflow
+/// period. Folding every reading through this monotonic accumulator keeps
+/// elapsed-time checks correct without needing a lock.
+static VIRTUAL_TICKS: Static<u32> = Static::new(0);
+
+/// Makes sure the timer pair configured via [`set_timer_for_timeout`] is
+/// running as a free-running virtual counter, starting it on first use.
+///
+/// Unlike the old design, this counter is never reset once started, so any
+/// number of [`Timeout`]s can read it independently.
+fn ensure_timer_running() -> Option<TimerId> {
+    let id = TIMER_ID.read();
+    if id == TimerId::None {
+        return None;
+    }
+
+    let (low_l, low_h) = id.regs();
+    let (high_l, high_h) = id.cascade().regs();
+    if !low_h.read().enabled() {
+        low_h.write(TimerControlSetting::new());
+        high_h.write(TimerControlSetting::new());
+        low_l.write(0);
+        high_l.write(0);
+        let high_ctl =
+            TimerControlSetting::new().with_tick_rate(TimerTickRate::Overflow).with_enabled(true);
+        let low_ctl = TimerControlSetting::new()
+            .with_tick_rate(TimerTickRate::CPU1024)
+            .with_overflow_irq(true)
+            .with_enabled(true);
+        high_h.write(high_ctl);
+        low_h.write(low_ctl);
+
+        // Without this, `Halt` would rely entirely on some unrelated
+        // interrupt (such as VBlank) already being enabled by the game. The
+        // low timer's overflow interrupt wakes it periodically, not at any
+        // particular deadline.
+        id.enable_irq();
+    }
+    Some(id)
+}
+
+/// Reads the current 32-bit virtual tick count of the timer pair configured
+/// via [`set_timer_for_timeout`], folding it through [`VIRTUAL_TICKS`] to
+/// correct for any tearing between the low and high halves.
+fn read_virtual_ticks(id: TimerId) -> u32 {
+    let (low_l, _) = id.regs();
+    let (high_l, _) = id.cascade().regs();
+    let raw = ((high_l.read() as u32) << 16) | low_l.read() as u32;
+    let ticks = raw.max(VIRTUAL_TICKS.read());
+    VIRTUAL_TICKS.write(ticks);
+    ticks
+}
+
 /// A timeout type used to prevent errors with SRAM from hanging the game.
+///
+/// Any number of `Timeout`s may exist and be checked independently; they all
+/// read the same free-running counter started via [`set_timer_for_timeout`].
 pub struct Timeout {
-    _lock_guard: RawMutexGuard<'static>,
-    active: bool,
-    timer_l: VolAddress<u16>,
-    timer_h: VolAddress<TimerControlSetting>,
+    id: Option<TimerId>,
+    start: u32,
 }
 impl Timeout {
     /// Creates a new timeout from the timer passed to [`set_timer_for_timeout`].
-    ///
-    /// ## Errors
-    ///
-    /// If another timeout has already been created.
     #[inline(never)]
-    pub fn new() -> Result<Self, Error> {
-        static TIMEOUT_LOCK: RawMutex = RawMutex::new();
-        let _lock_guard = match TIMEOUT_LOCK.try_lock() {
-            Some(x) => x,
-            None => return Err(Error::MediaInUse),
-        };
-        let id = TIMER_ID.read();
-        Ok(Timeout {
-            _lock_guard,
-            active: id != TimerId::None,
-            timer_l: match id {
-                TimerId::None => unsafe { VolAddress::new(0) },
-                TimerId::T0 => TM0CNT_L,
-                TimerId::T1 => TM1CNT_L,
-                TimerId::T2 => TM2CNT_L,
-                TimerId::T3 => TM3CNT_L,
-            },
-            timer_h: match id {
-                TimerId::None => unsafe { VolAddress::new(0) },
-                TimerId::T0 => TM0CNT_H,
-                TimerId::T1 => TM1CNT_H,
-                TimerId::T2 => TM2CNT_H,
-                TimerId::T3 => TM3CNT_H,
-            },
-        })
+    pub fn new() -> Self {
+        Timeout { id: None, start: 0 }
     }
 
-    /// Starts this timeout.
-    pub fn start(&self) {
-        if self.active {
-            self.timer_l.write(0);
-            let timer_ctl = TimerControlSetting::new()
-                .with_tick_rate(TimerTickRate::CPU1024)
-                .with_enabled(true);
-            self.timer_h.write(TimerControlSetting::new());
-            self.timer_h.write(timer_ctl);
-        }
+    /// Starts this timeout, capturing the current tick count as its deadline
+    /// baseline.
+    pub fn start(&mut self) {
+        self.id = ensure_timer_running();
+        self.start = self.id.map(read_virtual_ticks).unwrap_or(0);
+    }
+
+    /// Returns whether this timeout is backed by a real timer, i.e. whether
+    /// [`set_timer_for_timeout`] had been called before [`start`] was.
+    ///
+    /// If this is `false`, [`is_timeout_met`](Timeout::is_timeout_met) can
+    /// never become `true`, since there is no timer to measure elapsed time
+    /// with; callers that poll in a loop must check this themselves to avoid
+    /// looping forever.
+    pub(crate) fn is_active(&self) -> bool {
+        self.id.is_some()
     }
 
     /// Returns whether a number of milliseconds has passed since the last call
     /// to [`start`].
     pub fn is_timeout_met(&self, check_ms: u16) -> bool {
-        self.active && check_ms * 17 < self.timer_l.read()
+        match self.id {
+            Some(id) => read_virtual_ticks(id).wrapping_sub(self.start) >= check_ms as u32 * 17,
+            None => false,
+        }
     }
 }
 
-/// Tries to obtain a lock on the global lock for SRAM operations.
+/// The global lock used to guard SRAM operations against each other.
+///
+/// This is a reader/writer lock so that operations that only read save data,
+/// such as rendering a save-slot preview, can proceed concurrently with each
+/// other; only operations that write or erase SRAM need exclusive access.
+static MEDIA_LOCK: RwLock = RwLock::new();
+
+/// Tries to obtain an exclusive lock on the global lock for SRAM operations.
 ///
 /// This is used to prevent operations on SRAM types that have complex state
 /// from interfering with each other.
-pub fn lock_media() -> Result<RawMutexGuard<'static>, Error> {
-    static LOCK: RawMutex = RawMutex::new();
-    match LOCK.try_lock() {
+pub fn lock_media() -> Result<RwLockWriteGuard<'static>, Error> {
+    match MEDIA_LOCK.try_write() {
         Some(x) => Ok(x),
         None => Err(Error::MediaInUse),
     }
-}
\ No newline at end of file
+}
+
+/// Tries to obtain an exclusive lock on the global lock for SRAM operations,
+/// retrying for up to `ms` milliseconds on the timer configured via
+/// [`set_timer_for_timeout`] before giving up.
+pub fn lock_media_for(ms: u16) -> Result<RwLockWriteGuard<'static>, Error> {
+    match MEDIA_LOCK.try_write_for(ms) {
+        Some(x) => Ok(x),
+        None => Err(Error::MediaInUse),
+    }
+}
+
+/// Tries to obtain a shared (read) lock on the global lock for SRAM
+/// operations.
+///
+/// Any number of readers may hold this at once, as long as no exclusive
+/// lock is currently held via [`lock_media`].
+pub fn lock_media_read() -> Result<RwLockReadGuard<'static>, Error> {
+    match MEDIA_LOCK.try_read() {
+        Some(x) => Ok(x),
+        None => Err(Error::MediaInUse),
+    }
+}
+
+/// Tries to obtain a shared (read) lock on the global lock for SRAM
+/// operations, retrying for up to `ms` milliseconds on the timer configured
+/// via [`set_timer_for_timeout`] before giving up.
+pub fn lock_media_read_for(ms: u16) -> Result<RwLockReadGuard<'static>, Error> {
+    match MEDIA_LOCK.try_read_for(ms) {
+        Some(x) => Ok(x),
+        None => Err(Error::MediaInUse),
+    }
+}
@@ -0,0 +1,466 @@
+//! Synchronization primitives safe to share between the main program and
+//! interrupt handlers.
+//!
+//! The GBA has a single core, so these types do not need to worry about
+//! true parallelism. Instead, they exist to guard against reentrancy: an
+//! interrupt handler may run in the middle of code that is in the process
+//! of mutating shared state, and these types make sure that doesn't corrupt
+//! anything.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// A cell type that allows safely sharing plain data between interrupt
+/// handlers and the main program.
+///
+/// This is essentially a [`Cell`](core::cell::Cell) that is `Sync`, which is
+/// sound here because reads and writes of the types this is used with are
+/// atomic on the GBA's ARM7TDMI core.
+pub struct Static<T: Copy> {
+    value: UnsafeCell<T>,
+}
+unsafe impl<T: Copy> Sync for Static<T> {}
+impl<T: Copy> Static<T> {
+    /// Creates a new `Static` containing `value`.
+    pub const fn new(value: T) -> Self {
+        Static { value: UnsafeCell::new(value) }
+    }
+
+    /// Reads the current value.
+    pub fn read(&self) -> T {
+        unsafe { *self.value.get() }
+    }
+
+    /// Overwrites the current value.
+    pub fn write(&self, value: T) {
+        unsafe {
+            *self.value.get() = value;
+        }
+    }
+}
+
+/// Repeatedly calls `attempt` until it returns `Some`, halting the CPU
+/// between attempts so it draws no power while waiting. Shared by
+/// [`RawMutex::try_lock_for`] and [`RwLock`]'s `try_read_for`/`try_write_for`
+/// so all three agree on how a timed, polled wait behaves.
+///
+/// Gives up once `ms` milliseconds have passed on the timer configured via
+/// [`set_timer_for_timeout`](crate::save::utils::set_timer_for_timeout), or
+/// immediately if no such timer has been configured.
+fn poll_with_timeout<R>(ms: u16, mut attempt: impl FnMut() -> Option<R>) -> Option<R> {
+    if let Some(result) = attempt() {
+        return Some(result);
+    }
+    let mut timeout = crate::save::utils::Timeout::new();
+    timeout.start();
+    if !timeout.is_active() {
+        return None;
+    }
+    loop {
+        crate::bios::Halt();
+        if let Some(result) = attempt() {
+            return Some(result);
+        }
+        if timeout.is_timeout_met(ms) {
+            return None;
+        }
+    }
+}
+
+/// A mutual exclusion primitive that does not block, but instead fails
+/// immediately (or after a timeout) if the lock is already held.
+///
+/// This is meant to guard against reentrancy between the main program and
+/// interrupt handlers, not to provide blocking mutual exclusion between
+/// threads, as the GBA has none.
+pub struct RawMutex {
+    locked: AtomicBool,
+}
+impl RawMutex {
+    /// Creates a new, unlocked `RawMutex`.
+    pub const fn new() -> Self {
+        RawMutex { locked: AtomicBool::new(false) }
+    }
+
+    /// Attempts to acquire the lock, returning `None` if it is already held.
+    pub fn try_lock(&self) -> Option<RawMutexGuard> {
+        match self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(RawMutexGuard { lock: self }),
+            Err(_) => None,
+        }
+    }
+
+    /// Attempts to acquire the lock, retrying until `ms` milliseconds have
+    /// passed on the timer configured via
+    /// [`set_timer_for_timeout`](crate::save::utils::set_timer_for_timeout).
+    ///
+    /// Between attempts, this calls the BIOS `Halt` function so the CPU
+    /// does not needlessly burn battery while waiting; it wakes again on
+    /// the next interrupt, such as the configured timer or VBlank.
+    ///
+    /// Returns `None` if the deadline passes before the lock could be
+    /// acquired, mirroring the semantics of `WaitTimeoutResult::timed_out`
+    /// in `std`/`parking_lot`. If no timeout timer has been configured,
+    /// this gives up immediately after the first failed attempt.
+    pub fn try_lock_for(&self, ms: u16) -> Option<RawMutexGuard> {
+        poll_with_timeout(ms, || self.try_lock())
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A guard representing a held [`RawMutex`] lock.
+///
+/// The lock is released when this is dropped.
+pub struct RawMutexGuard<'a> {
+    lock: &'a RawMutex,
+}
+impl<'a> Drop for RawMutexGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+impl<'a> RawMutexGuard<'a> {
+    /// Returns the mutex locked by this guard, so it can be re-acquired
+    /// later. Used internally by [`Condvar`].
+    fn mutex(&self) -> &'a RawMutex {
+        self.lock
+    }
+}
+
+/// Indicates whether a [`Condvar::wait_timeout`] call returned because its
+/// deadline elapsed, or because it was notified.
+pub struct WaitTimeoutResult(bool);
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait timed out without a notification.
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A condition variable that parks the CPU until notified.
+///
+/// Unlike [`RawMutex`], which is meant to guard against reentrancy, this is
+/// meant to be waited on: [`wait`](Condvar::wait) releases a held
+/// [`RawMutexGuard`] and calls the BIOS `Halt` function so the CPU draws no
+/// power until woken by an interrupt (VBlank, DMA-complete, a timer, or a
+/// serial/SRAM-DMA interrupt), then re-acquires the lock.
+///
+/// [`notify_one`](Condvar::notify_one) and
+/// [`notify_all`](Condvar::notify_all) are safe to call from an interrupt
+/// handler. Since a BIOS interrupt wait may return spuriously, callers
+/// should re-check their predicate in a loop, as with any other condition
+/// variable:
+///
+/// ```no_run
+/// while !predicate() {
+///     guard = condvar.wait(guard);
+/// }
+/// ```
+pub struct Condvar {
+    generation: AtomicU32,
+}
+impl Condvar {
+    /// Creates a new `Condvar` with no waiters.
+    pub const fn new() -> Self {
+        Condvar { generation: AtomicU32::new(0) }
+    }
+
+    /// Releases `guard`, halts the CPU until notified, then re-acquires the
+    /// lock and returns a new guard for it.
+    pub fn wait<'a>(&self, guard: RawMutexGuard<'a>) -> RawMutexGuard<'a> {
+        let mutex = guard.mutex();
+        let start_generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        while self.generation.load(Ordering::Acquire) == start_generation {
+            crate::bios::Halt();
+        }
+        Self::reacquire(mutex)
+    }
+
+    /// Releases `guard`, halts the CPU until notified or until `ms`
+    /// milliseconds have passed on the timer configured via
+    /// [`set_timer_for_timeout`](crate::save::utils::set_timer_for_timeout),
+    /// then re-acquires the lock.
+    ///
+    /// The returned [`WaitTimeoutResult`] reports whether the deadline
+    /// elapsed before a notification was observed. If no timeout timer has
+    /// been configured, this reports a timeout immediately rather than
+    /// waiting forever.
+    pub fn wait_timeout<'a>(
+        &self,
+        guard: RawMutexGuard<'a>,
+        ms: u16,
+    ) -> (RawMutexGuard<'a>, WaitTimeoutResult) {
+        let mutex = guard.mutex();
+        let start_generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+
+        let mut timeout = crate::save::utils::Timeout::new();
+        timeout.start();
+
+        let mut timed_out = false;
+        while self.generation.load(Ordering::Acquire) == start_generation {
+            if !timeout.is_active() {
+                timed_out = true;
+                break;
+            }
+            crate::bios::Halt();
+            if timeout.is_timeout_met(ms) {
+                timed_out = true;
+                break;
+            }
+        }
+        (Self::reacquire(mutex), WaitTimeoutResult(timed_out))
+    }
+
+    /// Notifies a single waiter. Safe to call from an interrupt handler.
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Notifies all waiters. Safe to call from an interrupt handler.
+    ///
+    /// As all waiters simply retry their predicate, this behaves the same
+    /// as [`notify_one`](Condvar::notify_one).
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Spins on [`RawMutex::try_lock`] until it succeeds, halting the CPU
+    /// between attempts.
+    fn reacquire(mutex: &RawMutex) -> RawMutexGuard {
+        loop {
+            if let Some(guard) = mutex.try_lock() {
+                return guard;
+            }
+            crate::bios::Halt();
+        }
+    }
+}
+
+/// The number of spins [`Once::call_once`] will tolerate while waiting on a
+/// racing initializer before treating it as a deadlock in debug builds.
+const ONCE_SPIN_LIMIT: u32 = 1_000_000;
+
+/// A cell that runs an initializer closure exactly once, then caches the
+/// result.
+///
+/// Useful for probing hardware exactly once, such as detecting the cartridge
+/// chip type, and caching the result for later SRAM accesses.
+pub struct Once<T> {
+    lock: RawMutex,
+    completed: AtomicBool,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+unsafe impl<T: Sync + Send> Sync for Once<T> {}
+impl<T> Once<T> {
+    /// Creates a new, uninitialized `Once`.
+    pub const fn new() -> Self {
+        Once {
+            lock: RawMutex::new(),
+            completed: AtomicBool::new(false),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns whether [`call_once`](Once::call_once) has already run.
+    pub fn is_completed(&self) -> bool {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    /// Returns the cached value, or `None` if it hasn't been initialized yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Runs `f` the first time this is called, caching and returning a
+    /// reference to its result; later calls return the cached value without
+    /// running `f` again.
+    ///
+    /// The [`RawMutex`] used internally serializes the initializing caller
+    /// against a racing interrupt-context caller, which instead spins until
+    /// initialization completes.
+    ///
+    /// Since the GBA has no way to park a caller and resume it once some
+    /// other context has made progress, `call_once` (and [`Lazy::force`])
+    /// must never be invoked from an interrupt handler that could itself
+    /// preempt an in-progress call to `call_once` on the *same* `Once`: the
+    /// preempted caller can never resume to finish `f` and release the lock,
+    /// so the interrupt handler's spin would never end. In debug builds,
+    /// spinning for an implausibly long time is treated as exactly that bug
+    /// and panics instead of hanging the console forever.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if !self.is_completed() {
+            match self.lock.try_lock() {
+                Some(_guard) => {
+                    if !self.is_completed() {
+                        unsafe { (*self.value.get()).write(f()) };
+                        self.completed.store(true, Ordering::Release);
+                    }
+                }
+                // Another caller is already running `f`. Busy-wait rather
+                // than `Halt`, since this may itself be running from an
+                // interrupt handler that the initializing caller is waiting
+                // to return from.
+                None => {
+                    let mut spins: u32 = 0;
+                    while !self.is_completed() {
+                        spins += 1;
+                        debug_assert!(
+                            spins < ONCE_SPIN_LIMIT,
+                            "Once::call_once spun too long waiting for an in-progress \
+                             initializer to finish; this usually means call_once was \
+                             invoked from an interrupt handler that preempted another \
+                             call to call_once on the same Once, which can never resume"
+                        );
+                    }
+                }
+            }
+        }
+        self.get().unwrap()
+    }
+}
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if self.is_completed() {
+            unsafe { (*self.value.get()).assume_init_drop() };
+        }
+    }
+}
+
+/// A value that is lazily initialized from a closure the first time it is
+/// dereferenced, built on top of [`Once`].
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: UnsafeCell<Option<F>>,
+}
+unsafe impl<T: Sync + Send, F: Send> Sync for Lazy<T, F> {}
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Creates a new `Lazy` that will be initialized with `f` on first use.
+    pub const fn new(f: F) -> Self {
+        Lazy { once: Once::new(), init: UnsafeCell::new(Some(f)) }
+    }
+
+    /// Forces initialization, returning a reference to the value.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            let f = unsafe { (*this.init.get()).take() }.expect("Lazy instance has no initializer");
+            f()
+        })
+    }
+}
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
+
+/// The writer-held bit of [`RwLock`]'s state word. The remaining bits count
+/// the number of readers currently holding the lock.
+const RW_WRITER: u32 = 1 << 31;
+
+/// A reader/writer lock that does not block, failing immediately (or after a
+/// timeout) if the requested lock can't be acquired.
+///
+/// Like [`RawMutex`], this is meant to guard against reentrancy between the
+/// main program and interrupt handlers, not to provide blocking mutual
+/// exclusion between threads, as the GBA has none. Any number of readers may
+/// hold the lock at once, but a writer requires that no readers (or other
+/// writers) are currently holding it.
+pub struct RwLock {
+    state: AtomicU32,
+}
+impl RwLock {
+    /// Creates a new, unlocked `RwLock`.
+    pub const fn new() -> Self {
+        RwLock { state: AtomicU32::new(0) }
+    }
+
+    /// Attempts to acquire a shared (read) lock, returning `None` if a
+    /// writer currently holds it.
+    ///
+    /// In the uncontended case, this is a single compare-exchange against
+    /// the lock's state word.
+    pub fn try_read(&self) -> Option<RwLockReadGuard> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & RW_WRITER != 0 {
+                return None;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    /// Attempts to acquire the exclusive (write) lock, returning `None` if
+    /// any readers or another writer currently hold it.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard> {
+        match self.state.compare_exchange(0, RW_WRITER, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(RwLockWriteGuard { lock: self }),
+            Err(_) => None,
+        }
+    }
+
+    /// Attempts to acquire a shared (read) lock, retrying until `ms`
+    /// milliseconds have passed on the timer configured via
+    /// [`set_timer_for_timeout`](crate::save::utils::set_timer_for_timeout).
+    pub fn try_read_for(&self, ms: u16) -> Option<RwLockReadGuard> {
+        poll_with_timeout(ms, || self.try_read())
+    }
+
+    /// Attempts to acquire the exclusive (write) lock, retrying until `ms`
+    /// milliseconds have passed on the timer configured via
+    /// [`set_timer_for_timeout`](crate::save::utils::set_timer_for_timeout).
+    pub fn try_write_for(&self, ms: u16) -> Option<RwLockWriteGuard> {
+        poll_with_timeout(ms, || self.try_write())
+    }
+
+    fn unlock_read(&self) {
+        self.state.fetch_sub(1, Ordering::Release);
+    }
+
+    fn unlock_write(&self) {
+        self.state.store(0, Ordering::Release);
+    }
+}
+
+/// A guard representing a held shared (read) [`RwLock`] lock.
+///
+/// The lock is released when this is dropped.
+pub struct RwLockReadGuard<'a> {
+    lock: &'a RwLock,
+}
+impl<'a> Drop for RwLockReadGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.unlock_read();
+    }
+}
+
+/// A guard representing a held exclusive (write) [`RwLock`] lock.
+///
+/// The lock is released when this is dropped.
+pub struct RwLockWriteGuard<'a> {
+    lock: &'a RwLock,
+}
+impl<'a> Drop for RwLockWriteGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.unlock_write();
+    }
+}